@@ -1,6 +1,10 @@
+use parity_wasm::elements::{
+    External, FunctionType, Instruction, Module, Section, Type, ValueType,
+};
 use wasm_nm::{Options, Symbol, Symbols};
 
 use crate::errors::{Result, ValidationErr};
+use crate::gas::{inject_gas_counter, FlatCost};
 
 static PUBLIC_SYMBOLS: Options = Options {
     imports: true,
@@ -30,10 +34,28 @@ static REQUIRED_EXPORTS: &[&str] = &[
     "cosmwasm_api_0_6",
 ];
 
+/// The maximum number of linear-memory pages (64 KiB each) a contract may declare.
+/// Contracts requesting more than this on instantiation are rejected so that a single
+/// contract cannot exhaust a node's memory. Rust-compiled contracts routinely declare an
+/// initial memory well above a megabyte, so the cap is set at 512 pages (32 MiB): generous
+/// enough not to reject real contracts, but still a hard bound against absurd requests.
+static MAX_MEMORY_PAGES: u32 = 512;
+
 static EXTRA_IMPORT_MSG: &str = "WASM requires unsupported imports - version too new?";
 
 static MISSING_EXPORT_MSG: &str = "WASM doesn't have required exports - version too old?";
 
+static MEMORY_LIMIT_MSG: &str = "WASM requests more linear memory than the allowed maximum";
+
+static IMPORT_SIGNATURE_MSG: &str =
+    "WASM imports a supported function with an unexpected type signature";
+
+static START_SECTION_MSG: &str =
+    "WASM declares a start function, which would run code outside our entry points";
+
+static PARSE_ERROR_MSG: &str =
+    "WASM could not be parsed (malformed, or uses opcodes we don't support)";
+
 pub fn check_api_compatibility(wasm_code: &[u8]) -> Result<()> {
     let mut reader = std::io::Cursor::new(wasm_code);
     let symbols = wasm_nm::symbols(PUBLIC_SYMBOLS.clone(), &mut reader).unwrap();
@@ -49,6 +71,265 @@ pub fn check_api_compatibility(wasm_code: &[u8]) -> Result<()> {
         }
         .fail();
     }
+    // Parse the module once and share it across the structural checks below.
+    let module = deserialize_wasm(wasm_code)?;
+    check_memory_limit(&module, MAX_MEMORY_PAGES)?;
+    check_import_signatures(&module)?;
+    check_deterministic(&module)?;
+    Ok(())
+}
+
+/// Validates and canonicalizes an uploaded contract, returning the exact bytes to persist.
+///
+/// This is the single entry point the storage path calls when a contract is uploaded: it
+/// rejects incompatible or non-deterministic modules via [`check_api_compatibility`], strips
+/// the module to its canonical form with [`normalize_contract`], and injects deterministic
+/// gas metering with [`crate::gas::inject_gas_counter`]. Callers must store (and later
+/// instantiate) the returned bytes, not the original upload.
+pub fn prepare_contract(wasm_code: &[u8]) -> Result<Vec<u8>> {
+    check_api_compatibility(wasm_code)?;
+    let canonical = normalize_contract(wasm_code)?;
+    inject_gas_counter(&canonical, &FlatCost)
+}
+
+/// Parses contract bytes into a `Module`, surfacing a `ValidationErr` instead of panicking
+/// on malformed or unsupported input — these bytes are untrusted and must never crash a node.
+fn deserialize_wasm(wasm_code: &[u8]) -> Result<Module> {
+    match parity_wasm::deserialize_buffer(wasm_code) {
+        Ok(module) => Ok(module),
+        Err(_) => ValidationErr {
+            msg: PARSE_ERROR_MSG,
+        }
+        .fail(),
+    }
+}
+
+/// Produces the canonical bytes to be stored on-chain for a contract: all custom sections
+/// (`name`, `producers`, debug info, ...) are dropped because they bloat storage and play
+/// no part in execution, and a module declaring a `start` section is rejected outright
+/// since it would run arbitrary code at instantiation outside our controlled entry points.
+pub fn normalize_contract(wasm_code: &[u8]) -> Result<Vec<u8>> {
+    let mut module = deserialize_wasm(wasm_code)?;
+
+    if module.start_section().is_some() {
+        return ValidationErr {
+            msg: START_SECTION_MSG,
+        }
+        .fail();
+    }
+
+    module
+        .sections_mut()
+        .retain(|section| !matches!(section, Section::Custom(_)));
+
+    match parity_wasm::serialize(module) {
+        Ok(bytes) => Ok(bytes),
+        Err(_) => ValidationErr {
+            msg: PARSE_ERROR_MSG,
+        }
+        .fail(),
+    }
+}
+
+/// Scans every function body and rejects the module if it uses an opcode whose result is
+/// not guaranteed to be bit-identical across validator hardware: floating-point arithmetic
+/// and conversions (including the saturating `trunc_sat` family). Consensus requires that
+/// all validators compute the same result, so these opcodes are forbidden outright. The
+/// returned `ValidationErr` names the offending opcode.
+///
+/// SIMD/vector and atomic/threading opcodes are not enabled in our `parity_wasm` build, so a
+/// module using them fails to parse in `deserialize_wasm` and is rejected there. This is
+/// asserted end-to-end by `test_simd_and_atomics_rejected` rather than assumed here.
+fn check_deterministic(module: &Module) -> Result<()> {
+    if let Some(code) = module.code_section() {
+        for body in code.bodies() {
+            for instruction in body.code().elements() {
+                if let Some(opcode) = nondeterministic_opcode(instruction) {
+                    return ValidationErr { msg: opcode }.fail();
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the mnemonic of a non-deterministic opcode (floating-point op, float conversion,
+/// or saturating truncation), or `None` for deterministic instructions. The mnemonic is used
+/// verbatim as the `ValidationErr` message so the rejection names the offending opcode.
+fn nondeterministic_opcode(instruction: &Instruction) -> Option<&'static str> {
+    use Instruction::*;
+    let name = match instruction {
+        F32Load(_, _) => "f32.load",
+        F64Load(_, _) => "f64.load",
+        F32Store(_, _) => "f32.store",
+        F64Store(_, _) => "f64.store",
+        F32Const(_) => "f32.const",
+        F64Const(_) => "f64.const",
+        F32Eq => "f32.eq",
+        F32Ne => "f32.ne",
+        F32Lt => "f32.lt",
+        F32Gt => "f32.gt",
+        F32Le => "f32.le",
+        F32Ge => "f32.ge",
+        F64Eq => "f64.eq",
+        F64Ne => "f64.ne",
+        F64Lt => "f64.lt",
+        F64Gt => "f64.gt",
+        F64Le => "f64.le",
+        F64Ge => "f64.ge",
+        F32Abs => "f32.abs",
+        F32Neg => "f32.neg",
+        F32Ceil => "f32.ceil",
+        F32Floor => "f32.floor",
+        F32Trunc => "f32.trunc",
+        F32Nearest => "f32.nearest",
+        F32Sqrt => "f32.sqrt",
+        F32Add => "f32.add",
+        F32Sub => "f32.sub",
+        F32Mul => "f32.mul",
+        F32Div => "f32.div",
+        F32Min => "f32.min",
+        F32Max => "f32.max",
+        F32Copysign => "f32.copysign",
+        F64Abs => "f64.abs",
+        F64Neg => "f64.neg",
+        F64Ceil => "f64.ceil",
+        F64Floor => "f64.floor",
+        F64Trunc => "f64.trunc",
+        F64Nearest => "f64.nearest",
+        F64Sqrt => "f64.sqrt",
+        F64Add => "f64.add",
+        F64Sub => "f64.sub",
+        F64Mul => "f64.mul",
+        F64Div => "f64.div",
+        F64Min => "f64.min",
+        F64Max => "f64.max",
+        F64Copysign => "f64.copysign",
+        I32TruncSF32 => "i32.trunc_f32_s",
+        I32TruncUF32 => "i32.trunc_f32_u",
+        I32TruncSF64 => "i32.trunc_f64_s",
+        I32TruncUF64 => "i32.trunc_f64_u",
+        I64TruncSF32 => "i64.trunc_f32_s",
+        I64TruncUF32 => "i64.trunc_f32_u",
+        I64TruncSF64 => "i64.trunc_f64_s",
+        I64TruncUF64 => "i64.trunc_f64_u",
+        F32ConvertSI32 => "f32.convert_i32_s",
+        F32ConvertUI32 => "f32.convert_i32_u",
+        F32ConvertSI64 => "f32.convert_i64_s",
+        F32ConvertUI64 => "f32.convert_i64_u",
+        F32DemoteF64 => "f32.demote_f64",
+        F64ConvertSI32 => "f64.convert_i32_s",
+        F64ConvertUI32 => "f64.convert_i32_u",
+        F64ConvertSI64 => "f64.convert_i64_s",
+        F64ConvertUI64 => "f64.convert_i64_u",
+        F64PromoteF32 => "f64.promote_f32",
+        I32ReinterpretF32 => "i32.reinterpret_f32",
+        I64ReinterpretF64 => "i64.reinterpret_f64",
+        F32ReinterpretI32 => "f32.reinterpret_i32",
+        F64ReinterpretI64 => "f64.reinterpret_i64",
+        I32TruncSatF32S => "i32.trunc_sat_f32_s",
+        I32TruncSatF32U => "i32.trunc_sat_f32_u",
+        I32TruncSatF64S => "i32.trunc_sat_f64_s",
+        I32TruncSatF64U => "i32.trunc_sat_f64_u",
+        I64TruncSatF32S => "i64.trunc_sat_f32_s",
+        I64TruncSatF32U => "i64.trunc_sat_f32_u",
+        I64TruncSatF64S => "i64.trunc_sat_f64_s",
+        I64TruncSatF64U => "i64.trunc_sat_f64_u",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Returns the `(params, results)` signature we expect a supported import to be declared
+/// with. A name that is not in this table has no signature constraint (its presence is
+/// already governed by [`SUPPORTED_IMPORTS`]).
+fn expected_import_signature(name: &str) -> Option<(Vec<ValueType>, Vec<ValueType>)> {
+    use ValueType::I32;
+    match name {
+        "read_db" => Some((vec![I32], vec![I32])),
+        "write_db" => Some((vec![I32, I32], vec![])),
+        "canonicalize_address" => Some((vec![I32, I32], vec![I32])),
+        "humanize_address" => Some((vec![I32, I32], vec![I32])),
+        _ => None,
+    }
+}
+
+/// Verifies that every imported function we recognise is declared with its expected type
+/// signature. Checking only the name (as `import_requirements_satisfied` does) would let a
+/// contract import e.g. `read_db` with the wrong arity and only fault at call time.
+fn check_import_signatures(module: &Module) -> Result<()> {
+    let types: Vec<&FunctionType> = module
+        .type_section()
+        .map(|section| {
+            section
+                .types()
+                .iter()
+                .map(|Type::Function(func)| func)
+                .collect()
+        })
+        .unwrap_or_else(Vec::new);
+
+    if let Some(imports) = module.import_section() {
+        for entry in imports.entries() {
+            if let External::Function(type_index) = entry.external() {
+                if let Some((params, results)) = expected_import_signature(entry.field()) {
+                    let actual = match types.get(*type_index as usize) {
+                        Some(func) => *func,
+                        None => {
+                            return ValidationErr {
+                                msg: IMPORT_SIGNATURE_MSG,
+                            }
+                            .fail()
+                        }
+                    };
+                    if actual.params() != params.as_slice()
+                        || actual.results() != results.as_slice()
+                    {
+                        return ValidationErr {
+                            msg: IMPORT_SIGNATURE_MSG,
+                        }
+                        .fail();
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses the module's memory declarations and rejects any contract whose initial (or
+/// declared maximum) linear memory exceeds `max_pages`. Both memories defined in the
+/// memory section and memories pulled in through the import section are inspected, since
+/// either grants the instance that much address space.
+fn check_memory_limit(module: &Module, max_pages: u32) -> Result<()> {
+    let declared = module
+        .memory_section()
+        .map(|section| section.entries().iter().map(|m| *m.limits()).collect())
+        .unwrap_or_else(Vec::new);
+
+    let imported = module
+        .import_section()
+        .map(|section| {
+            section
+                .entries()
+                .iter()
+                .filter_map(|entry| match entry.external() {
+                    External::Memory(memory_type) => Some(*memory_type.limits()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_else(Vec::new);
+
+    for limits in declared.into_iter().chain(imported.into_iter()) {
+        let requested = limits.maximum().unwrap_or_else(|| limits.initial());
+        if limits.initial() > max_pages || requested > max_pages {
+            return ValidationErr {
+                msg: MEMORY_LIMIT_MSG,
+            }
+            .fail();
+        }
+    }
     Ok(())
 }
 
@@ -196,4 +477,189 @@ mod test {
             Ok(_) => panic!("Didn't reject wasm with invalid api"),
         }
     }
+
+    #[test]
+    fn test_memory_limit() {
+        use crate::errors::Error;
+        use wabt::wat2wasm;
+
+        // a typical Rust-compiled contract declares well over a megabyte and is accepted
+        let small = wat2wasm(r#"(module (memory 17))"#).unwrap();
+        check_memory_limit(&deserialize_wasm(&small).unwrap(), MAX_MEMORY_PAGES).unwrap();
+
+        // right at the cap is still accepted
+        let exact = wat2wasm(r#"(module (memory 512))"#).unwrap();
+        check_memory_limit(&deserialize_wasm(&exact).unwrap(), MAX_MEMORY_PAGES).unwrap();
+
+        // an excessive initial request is rejected
+        let huge = wat2wasm(r#"(module (memory 513))"#).unwrap();
+        match check_memory_limit(&deserialize_wasm(&huge).unwrap(), MAX_MEMORY_PAGES) {
+            Err(Error::ValidationErr { msg }) => assert_eq!(msg, MEMORY_LIMIT_MSG),
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Didn't reject wasm requesting too much memory"),
+        }
+
+        // a small initial but a large declared maximum is also rejected
+        let huge_max = wat2wasm(r#"(module (memory 1 1024))"#).unwrap();
+        match check_memory_limit(&deserialize_wasm(&huge_max).unwrap(), MAX_MEMORY_PAGES) {
+            Err(Error::ValidationErr { msg }) => assert_eq!(msg, MEMORY_LIMIT_MSG),
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Didn't reject wasm with an excessive maximum"),
+        }
+    }
+
+    #[test]
+    fn test_import_signatures() {
+        use crate::errors::Error;
+        use wabt::wat2wasm;
+
+        // read_db declared exactly as (i32) -> i32 passes
+        let good = wat2wasm(
+            r#"(module (import "env" "read_db" (func (param i32) (result i32))))"#,
+        )
+        .unwrap();
+        check_import_signatures(&deserialize_wasm(&good).unwrap()).unwrap();
+
+        // read_db with a missing result is rejected
+        let wrong_results =
+            wat2wasm(r#"(module (import "env" "read_db" (func (param i32))))"#).unwrap();
+        match check_import_signatures(&deserialize_wasm(&wrong_results).unwrap()) {
+            Err(Error::ValidationErr { msg }) => assert_eq!(msg, IMPORT_SIGNATURE_MSG),
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Didn't reject import with wrong results"),
+        }
+
+        // read_db with the wrong arity is rejected
+        let wrong_params = wat2wasm(
+            r#"(module (import "env" "read_db" (func (param i32 i32) (result i32))))"#,
+        )
+        .unwrap();
+        match check_import_signatures(&deserialize_wasm(&wrong_params).unwrap()) {
+            Err(Error::ValidationErr { msg }) => assert_eq!(msg, IMPORT_SIGNATURE_MSG),
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Didn't reject import with wrong params"),
+        }
+
+        // an import we don't recognise is left untouched by the signature check
+        let unknown = wat2wasm(
+            r#"(module (import "env" "future_function" (func (param f64))))"#,
+        )
+        .unwrap();
+        check_import_signatures(&deserialize_wasm(&unknown).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_deterministic() {
+        use crate::errors::Error;
+        use wabt::wat2wasm;
+
+        // pure integer arithmetic is allowed
+        let integer = wat2wasm(
+            r#"(module (func (result i32) i32.const 1 i32.const 2 i32.add))"#,
+        )
+        .unwrap();
+        check_deterministic(&deserialize_wasm(&integer).unwrap()).unwrap();
+
+        // a floating-point op is rejected
+        let floating = wat2wasm(
+            r#"(module (func (param f32 f32) (result f32) get_local 0 get_local 1 f32.add))"#,
+        )
+        .unwrap();
+        match check_deterministic(&deserialize_wasm(&floating).unwrap()) {
+            // the error names the offending opcode
+            Err(Error::ValidationErr { msg }) => assert_eq!(msg, "f32.add"),
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Didn't reject wasm using floating point"),
+        }
+
+        // a float conversion is rejected too
+        let convert = wat2wasm(
+            r#"(module (func (param f32) (result i32) get_local 0 i32.trunc_f32_s))"#,
+        )
+        .unwrap();
+        match check_deterministic(&deserialize_wasm(&convert).unwrap()) {
+            Err(Error::ValidationErr { msg }) => assert_eq!(msg, "i32.trunc_f32_s"),
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Didn't reject wasm using a float conversion"),
+        }
+
+        // the saturating truncations are non-deterministic too and must be named
+        let trunc_sat = wat2wasm(
+            r#"(module (func (param f32) (result i32) get_local 0 i32.trunc_sat_f32_s))"#,
+        )
+        .unwrap();
+        match check_deterministic(&deserialize_wasm(&trunc_sat).unwrap()) {
+            Err(Error::ValidationErr { msg }) => assert_eq!(msg, "i32.trunc_sat_f32_s"),
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Didn't reject wasm using a saturating conversion"),
+        }
+    }
+
+    #[test]
+    fn test_simd_and_atomics_rejected() {
+        use wabt::wat2wasm;
+
+        // SIMD/vector and atomic/threading opcodes are not enabled in our parity_wasm build,
+        // so the module must be rejected (here at parse time) rather than sneaking past the
+        // float-only denylist. Proving this empirically guards against a future build that
+        // flips those features on without extending the denylist.
+        let simd = wat2wasm(
+            r#"(module (func (result v128) v128.const i32x4 0 0 0 0))"#,
+        )
+        .unwrap();
+        assert!(deserialize_wasm(&simd).is_err(), "SIMD module not rejected");
+
+        let atomic = wat2wasm(
+            r#"(module (memory 1 1 shared)
+                 (func (result i32) i32.const 0 i32.atomic.load))"#,
+        )
+        .unwrap();
+        assert!(
+            deserialize_wasm(&atomic).is_err(),
+            "atomic module not rejected"
+        );
+    }
+
+    #[test]
+    fn test_unparseable_is_rejected_not_panicking() {
+        use crate::errors::Error;
+
+        // SIMD, atomic and otherwise malformed modules cannot be parsed; they must surface a
+        // ValidationErr rather than panic the node on untrusted input.
+        match deserialize_wasm(b"\x00asm\x01\x00\x00\x00\xff\xff") {
+            Err(Error::ValidationErr { msg }) => assert_eq!(msg, PARSE_ERROR_MSG),
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Didn't reject malformed wasm"),
+        }
+    }
+
+    #[test]
+    fn test_normalize_contract() {
+        use crate::errors::Error;
+        use parity_wasm::elements::Section;
+        use wabt::wat2wasm;
+
+        // a module with a custom section comes back without it, still deserializable
+        let with_custom = wat2wasm(
+            r#"(module (func (export "run")) (@custom "name" "\00"))"#,
+        )
+        .unwrap();
+        let normalized = normalize_contract(&with_custom).unwrap();
+        let module: Module = parity_wasm::deserialize_buffer(&normalized).unwrap();
+        assert!(module
+            .sections()
+            .iter()
+            .all(|section| !matches!(section, Section::Custom(_))));
+
+        // a module declaring a start function is rejected
+        let with_start = wat2wasm(
+            r#"(module (func $s) (start $s))"#,
+        )
+        .unwrap();
+        match normalize_contract(&with_start) {
+            Err(Error::ValidationErr { msg }) => assert_eq!(msg, START_SECTION_MSG),
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Didn't reject wasm with a start section"),
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,386 @@
+//! Deterministic gas-metering injection.
+//!
+//! Before a stored contract is instantiated we rewrite its code section so that every
+//! straight-line run of instructions is preceded by a call to an injected host import
+//! `gas(i32)` that charges the accumulated cost of that run. Because the charge happens
+//! *before* the block's instructions execute, and because loop bodies are their own
+//! metered blocks, execution cost is bounded and identical on every validator regardless
+//! of how the host schedules work.
+//!
+//! The entry point is [`inject_gas_counter`]; it is applied next to
+//! [`crate::compatability::check_api_compatibility`] on the contract bytes that get stored.
+
+use std::collections::HashMap;
+
+use parity_wasm::builder;
+use parity_wasm::elements::{
+    External, ImportEntry, Instruction, Instructions, Internal, Module, ValueType,
+};
+
+use crate::errors::{Result, ValidationErr};
+
+/// Name of the host function the injected charges call into.
+static GAS_FUNCTION: &str = "gas";
+
+/// Module namespace the `gas` import is declared under, matching the other env imports.
+static GAS_MODULE: &str = "env";
+
+static ALREADY_METERED_MSG: &str =
+    "WASM already imports a `gas` function - refusing to meter it twice";
+
+static PARSE_ERROR_MSG: &str = "WASM could not be parsed for gas metering";
+
+static SERIALIZE_ERROR_MSG: &str = "metered WASM could not be serialized";
+
+/// Assigns an execution cost to a single instruction. Implementors return the number of
+/// gas units the instruction is worth; a flat cost of 1 is provided by [`FlatCost`].
+pub trait CostTable {
+    fn cost(&self, instruction: &Instruction) -> u32;
+}
+
+/// Charges a flat cost of one gas unit per instruction. This is the default metering
+/// policy and is sufficient to make execution deterministic and bounded.
+pub struct FlatCost;
+
+impl CostTable for FlatCost {
+    fn cost(&self, _instruction: &Instruction) -> u32 {
+        1
+    }
+}
+
+/// A cost table backed by a per-opcode map, falling back to a flat `default` for opcodes
+/// that are not listed. Keys are the names returned by [`mnemonic`]: only the control-flow
+/// opcodes `call`, `call_indirect`, `loop`, `block`, `if`, `br`, `br_if` and `br_table` have
+/// their own keys, so callers can price these above cheap ones. Every other instruction is
+/// looked up under the single key `"default"` and cannot be priced individually.
+pub struct OpcodeCost {
+    costs: HashMap<&'static str, u32>,
+    default: u32,
+}
+
+impl OpcodeCost {
+    pub fn new(default: u32, costs: HashMap<&'static str, u32>) -> Self {
+        OpcodeCost { costs, default }
+    }
+}
+
+impl CostTable for OpcodeCost {
+    fn cost(&self, instruction: &Instruction) -> u32 {
+        self.costs
+            .get(mnemonic(instruction))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Rewrites `wasm` so that each metered block charges its total cost up front through the
+/// injected `gas` import, returning the re-serialized module.
+///
+/// The rewrite is refused on modules that already import a `gas` function, so it is safe to
+/// run without tracking whether a given blob was metered before.
+pub fn inject_gas_counter(wasm: &[u8], cost_table: &dyn CostTable) -> Result<Vec<u8>> {
+    let module = deserialize(wasm)?;
+
+    if imports_gas(&module) {
+        return ValidationErr {
+            msg: ALREADY_METERED_MSG,
+        }
+        .fail();
+    }
+
+    // The `gas` import is appended to the import section, so its function index is the
+    // number of functions already imported; every *local* function (index >= that count)
+    // shifts up by one. Imported functions keep their indices. Relocate before metering so
+    // the `Call(gas_index)` charges we splice in are not themselves shifted.
+    let gas_index = count_imported_functions(&module);
+    let mut module = add_gas_import(module);
+    shift_function_indices(&mut module, gas_index);
+
+    if let Some(code) = module.code_section_mut() {
+        for body in code.bodies_mut() {
+            let metered = meter_function(body.code().elements(), gas_index, cost_table);
+            *body.code_mut() = Instructions::new(metered);
+        }
+    }
+
+    serialize(module)
+}
+
+fn deserialize(wasm: &[u8]) -> Result<Module> {
+    match parity_wasm::deserialize_buffer(wasm) {
+        Ok(module) => Ok(module),
+        Err(_) => ValidationErr {
+            msg: PARSE_ERROR_MSG,
+        }
+        .fail(),
+    }
+}
+
+fn serialize(module: Module) -> Result<Vec<u8>> {
+    match parity_wasm::serialize(module) {
+        Ok(bytes) => Ok(bytes),
+        Err(_) => ValidationErr {
+            msg: SERIALIZE_ERROR_MSG,
+        }
+        .fail(),
+    }
+}
+
+fn imports_gas(module: &Module) -> bool {
+    module
+        .import_section()
+        .map(|section| {
+            section.entries().iter().any(|entry| {
+                entry.field() == GAS_FUNCTION && matches!(entry.external(), External::Function(_))
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// Number of functions the module already imports. This is the function index the appended
+/// `gas` import takes, and the boundary between imported and local function indices.
+fn count_imported_functions(module: &Module) -> u32 {
+    module
+        .import_section()
+        .map(|section| {
+            section
+                .entries()
+                .iter()
+                .filter(|entry| matches!(entry.external(), External::Function(_)))
+                .count() as u32
+        })
+        .unwrap_or(0)
+}
+
+/// Declares the `(i32) -> ()` type for `gas` and appends the import referencing it. The
+/// builder appends the import, so the caller must shift local function indices afterwards.
+fn add_gas_import(module: Module) -> Module {
+    let mut builder = builder::from_module(module);
+    let gas_type = builder.push_signature(
+        builder::signature()
+            .with_param(ValueType::I32)
+            .build_sig(),
+    );
+    builder
+        .with_import(ImportEntry::new(
+            GAS_MODULE.into(),
+            GAS_FUNCTION.into(),
+            External::Function(gas_type),
+        ))
+        .build()
+}
+
+/// Shifts every function-index reference at or above `threshold` up by one, accounting for
+/// the `gas` import inserted at `threshold`. Calls, exports, the start function, and table
+/// element segments all embed function indices and are rewritten here.
+fn shift_function_indices(module: &mut Module, threshold: u32) {
+    if let Some(code) = module.code_section_mut() {
+        for body in code.bodies_mut() {
+            for instruction in body.code_mut().elements_mut() {
+                if let Instruction::Call(index) = instruction {
+                    if *index >= threshold {
+                        *index += 1;
+                    }
+                }
+            }
+        }
+    }
+    if let Some(exports) = module.export_section_mut() {
+        for entry in exports.entries_mut() {
+            if let Internal::Function(index) = entry.internal_mut() {
+                if *index >= threshold {
+                    *index += 1;
+                }
+            }
+        }
+    }
+    if let Some(start) = module.start_section() {
+        if start >= threshold {
+            module.set_start_section(start + 1);
+        }
+    }
+    if let Some(elements) = module.elements_section_mut() {
+        for segment in elements.entries_mut() {
+            for index in segment.members_mut() {
+                if *index >= threshold {
+                    *index += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Splits a function body into metered blocks and prepends a charge call to each.
+///
+/// A new block starts at the function entry and after any control-flow instruction, since
+/// control flow is the only way execution can diverge from straight-line order. The cost of
+/// the control-flow instruction itself is attributed to the block it terminates, so the
+/// charge is always paid before the branch is taken.
+fn meter_function(
+    original: &[Instruction],
+    gas_index: u32,
+    cost_table: &dyn CostTable,
+) -> Vec<Instruction> {
+    let mut output: Vec<Instruction> = Vec::with_capacity(original.len() * 2);
+    let mut block: Vec<Instruction> = Vec::new();
+    let mut block_cost: u32 = 0;
+
+    let flush = |output: &mut Vec<Instruction>, block: &mut Vec<Instruction>, cost: &mut u32| {
+        if !block.is_empty() || *cost > 0 {
+            output.push(Instruction::I32Const(*cost as i32));
+            output.push(Instruction::Call(gas_index));
+            output.append(block);
+            *cost = 0;
+        }
+    };
+
+    for instruction in original {
+        block_cost += cost_table.cost(instruction);
+        block.push(instruction.clone());
+        if starts_new_block(instruction) {
+            flush(&mut output, &mut block, &mut block_cost);
+        }
+    }
+    flush(&mut output, &mut block, &mut block_cost);
+    output
+}
+
+/// Returns true when the next instruction begins a fresh metered block, i.e. `instruction`
+/// is a control-flow boundary.
+fn starts_new_block(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Block(_)
+            | Instruction::Loop(_)
+            | Instruction::If(_)
+            | Instruction::Else
+            | Instruction::End
+            | Instruction::Br(_)
+            | Instruction::BrIf(_)
+            | Instruction::BrTable(_)
+            | Instruction::Return
+            | Instruction::Call(_)
+            | Instruction::CallIndirect(_, _)
+    )
+}
+
+/// Stable mnemonic used as the key in an [`OpcodeCost`] table. Only the control-flow opcodes
+/// are named; every other instruction maps to `"default"`.
+fn mnemonic(instruction: &Instruction) -> &'static str {
+    match instruction {
+        Instruction::Call(_) => "call",
+        Instruction::CallIndirect(_, _) => "call_indirect",
+        Instruction::Loop(_) => "loop",
+        Instruction::Block(_) => "block",
+        Instruction::If(_) => "if",
+        Instruction::Br(_) => "br",
+        Instruction::BrIf(_) => "br_if",
+        Instruction::BrTable(_) => "br_table",
+        _ => "default",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wabt::wat2wasm;
+
+    static WAT_ADD: &str = r#"
+        (module
+          (func $add_one (export "add_one") (param i32) (result i32)
+            get_local 0
+            i32.const 1
+            i32.add))
+    "#;
+
+    // A contract that imports a host function, as every real cosmwasm contract does. The
+    // gas import must land *after* `read_db`, exercising the index arithmetic.
+    static WAT_WITH_IMPORT: &str = r#"
+        (module
+          (import "env" "read_db" (func $read (param i32) (result i32)))
+          (func $run (export "run") (result i32)
+            i32.const 1
+            call $read))
+    "#;
+
+    fn metered_module(wat: &str) -> Module {
+        let wasm = wat2wasm(wat).unwrap();
+        let injected = inject_gas_counter(&wasm, &FlatCost).unwrap();
+        parity_wasm::deserialize_buffer(&injected).unwrap()
+    }
+
+    /// Function index of the imported `gas`, counting only imported functions in order.
+    fn gas_function_index(module: &Module) -> u32 {
+        let mut index = 0;
+        for entry in module.import_section().unwrap().entries() {
+            if let External::Function(_) = entry.external() {
+                if entry.field() == GAS_FUNCTION {
+                    return index;
+                }
+                index += 1;
+            }
+        }
+        panic!("gas import not found");
+    }
+
+    #[test]
+    fn injects_gas_import() {
+        let module = metered_module(WAT_ADD);
+        assert!(imports_gas(&module));
+    }
+
+    #[test]
+    fn prepends_charge_to_each_block() {
+        let module = metered_module(WAT_ADD);
+        let gas_index = gas_function_index(&module);
+        let body = &module.code_section().unwrap().bodies()[0];
+        // The first metered block must begin with a constant cost and a call into `gas`.
+        match body.code().elements() {
+            [Instruction::I32Const(_), Instruction::Call(idx), ..] => {
+                assert_eq!(*idx, gas_index)
+            }
+            other => panic!("function not metered at entry: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_to_meter_twice() {
+        let wasm = wat2wasm(WAT_ADD).unwrap();
+        let once = inject_gas_counter(&wasm, &FlatCost).unwrap();
+        // Running the metered output back through the injector must be rejected.
+        inject_gas_counter(&once, &FlatCost).unwrap_err();
+    }
+
+    #[test]
+    fn meters_module_with_imports() {
+        let wasm = wat2wasm(WAT_WITH_IMPORT).unwrap();
+        let injected = inject_gas_counter(&wasm, &FlatCost).unwrap();
+
+        // The output must round-trip back into a structurally valid module.
+        let module: Module = parity_wasm::deserialize_buffer(&injected).unwrap();
+
+        // `gas` is appended after the contract's own import `read_db`, so it is index 1.
+        let gas_index = gas_function_index(&module);
+        assert_eq!(gas_index, 1);
+
+        let body = &module.code_section().unwrap().bodies()[0];
+        let code = body.code().elements();
+        // Charges resolve to the gas import...
+        match code {
+            [Instruction::I32Const(_), Instruction::Call(idx), ..] => {
+                assert_eq!(*idx, gas_index)
+            }
+            other => panic!("function not metered at entry: {:?}", other),
+        }
+        // ...and the original call to the import `read_db` (index 0) is left untouched.
+        assert!(code
+            .iter()
+            .any(|instruction| matches!(instruction, Instruction::Call(0))));
+    }
+
+    #[test]
+    fn rejects_malformed_wasm() {
+        // Garbage bytes must surface a ValidationErr rather than panicking the node.
+        inject_gas_counter(b"\x00not really wasm", &FlatCost).unwrap_err();
+    }
+}
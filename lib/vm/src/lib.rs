@@ -0,0 +1,3 @@
+pub mod compatability;
+pub mod errors;
+pub mod gas;